@@ -3,8 +3,12 @@
 use std::{
     fmt,
     hash::{Hash, Hasher},
+    marker::PhantomData,
     ptr,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+        RwLock,
+    },
 };
 use {
     dispatcher::{self, Dispatch},
@@ -13,15 +17,101 @@ use {
 };
 
 lazy_static! {
-    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry {
-        callsites: Vec::new(),
-        dispatchers: Vec::new(),
-    });
+    static ref REGISTRY: Registry = Registry {
+        callsites: CallsiteList::new(),
+        dispatchers: RwLock::new(Vec::new()),
+    };
 }
 
+/// Bumped every time `Interest` is recomputed for the whole registry, so that
+/// code which caches a callsite's `Interest` (such as the filtering macros)
+/// can cheaply tell whether it might be stale, without re-locking
+/// `dispatchers` on every hot-path check.
+static INTEREST_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
 struct Registry {
-    callsites: Vec<&'static Callsite>,
-    dispatchers: Vec<dispatcher::Registrar>,
+    callsites: CallsiteList,
+    dispatchers: RwLock<Vec<dispatcher::Registrar>>,
+}
+
+/// An intrusive, append-only, lock-free list of every `Callsite` that has
+/// ever been registered.
+///
+/// The list is published as a single `head` pointer, updated with a
+/// compare-and-swap, so pushing onto the list never blocks another thread
+/// that is pushing onto (or walking) the list at the same time. `register`
+/// itself still holds the `dispatchers` write lock around its push, so
+/// concurrent `register` calls are serialized by that lock. Nodes are
+/// leaked for the lifetime of the program: a `Callsite` is always `&'static`,
+/// so a node could never be freed anyway, and leaking it is what makes the
+/// CAS-push sound (no ABA, no use-after-free of the old head).
+struct CallsiteList {
+    head: AtomicPtr<Node>,
+}
+
+struct Node {
+    callsite: &'static Callsite,
+    next: AtomicPtr<Node>,
+}
+
+impl CallsiteList {
+    fn new() -> Self {
+        CallsiteList {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Pushes a new callsite onto the front of the list.
+    fn push(&self, callsite: &'static Callsite) {
+        let node = Box::leak(Box::new(Node {
+            callsite,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            node.next.store(head, Ordering::Relaxed);
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Returns an iterator over every currently registered callsite.
+    fn iter(&self) -> Iter<'_> {
+        Iter {
+            next: self.head.load(Ordering::Acquire),
+            _lifetime: PhantomData,
+        }
+    }
+
+    /// Clears the list so `iter()` stops yielding any of its current
+    /// entries.
+    ///
+    /// This does *not* free the leaked `Node`s behind the old head: they're
+    /// unreachable once the head is reset, but a `Callsite` is `'static` and
+    /// was never going to be freed anyway, so there's nothing to reclaim.
+    fn reset(&self) {
+        self.head.store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+struct Iter<'a> {
+    next: *const Node,
+    _lifetime: PhantomData<&'a CallsiteList>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'static Callsite;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = unsafe { self.next.as_ref() }?;
+        self.next = node.next.load(Ordering::Acquire);
+        Some(node.callsite)
+    }
 }
 
 /// Trait implemented by callsites.
@@ -59,45 +149,158 @@ pub struct Identifier(&'static Callsite);
 /// This should be called once per callsite after the callsite has been
 /// constructed.
 pub fn register(callsite: &'static Callsite) {
-    let mut registry = REGISTRY.lock().unwrap();
     let meta = callsite.metadata();
-    registry.dispatchers.retain(|registrar| {
-        match registrar.try_register(meta) {
-            Some(interest) => {
-                callsite.add_interest(interest);
-                true
-            }
-            // TODO: if the dispatcher has been dropped, should we invalidate
-            // any callsites that it previously enabled?
-            None => false,
+    let mut dispatchers = REGISTRY.dispatchers.write().unwrap();
+    let mut dead = false;
+    dispatchers.retain(|registrar| match registrar.try_register(meta) {
+        Some(interest) => {
+            callsite.add_interest(interest);
+            true
+        }
+        None => {
+            // The dispatcher has been dropped. Don't just drop it on the
+            // floor: other callsites registered earlier may still be holding
+            // onto `Interest` it granted them, so the whole registry needs
+            // to be resynced against the dispatchers that are left.
+            dead = true;
+            false
         }
     });
-    registry.callsites.push(callsite);
+    if dead {
+        rebuild_interest_locked(&mut dispatchers);
+    }
+    // Publish the callsite to `REGISTRY.callsites` *before* releasing the
+    // `dispatchers` write lock. `register_dispatch` takes that same lock to
+    // append its registrar, then walks `REGISTRY.callsites` without holding
+    // it; if we dropped the lock first, a `register_dispatch` racing with
+    // us could slip in, finish its scan, and never see this callsite at
+    // all, permanently excluding it from that dispatcher's interest.
+    // Pushing first means any such `register_dispatch` has to wait for us
+    // to finish, so it's guaranteed to observe this callsite.
+    REGISTRY.callsites.push(callsite);
+    drop(dispatchers);
 }
 
 pub(crate) fn register_dispatch(dispatch: &Dispatch) {
-    let mut registry = REGISTRY.lock().unwrap();
-    registry.dispatchers.push(dispatch.registrar());
-    for callsite in &registry.callsites {
+    REGISTRY.dispatchers.write().unwrap().push(dispatch.registrar());
+    for callsite in REGISTRY.callsites.iter() {
         let interest = dispatch.register_callsite(callsite.metadata());
         callsite.add_interest(interest);
     }
+    // A new dispatcher can escalate interest for every pre-existing
+    // callsite, so anything relying on `interest_generation()` to tell
+    // whether its cached interest might be stale needs to see this bump
+    // too, not just the `rebuild_interest*` family.
+    INTEREST_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Invalidates cached `Interest` after a `Dispatch` has been dropped.
+///
+/// A dropped `Dispatch` can no longer be upgraded from its `Registrar`, so
+/// any `Interest` it previously granted a callsite would otherwise be stuck
+/// enabled forever, pointing at a subscriber that no longer exists. This
+/// prunes dead registrars out of the registry and recomputes interest for
+/// every registered callsite from the dispatchers that are still alive.
+///
+/// This should be called from `Dispatch`'s `Drop` impl.
+pub(crate) fn reset_dispatch() {
+    let mut dispatchers = REGISTRY.dispatchers.write().unwrap();
+    rebuild_interest_locked(&mut dispatchers);
+}
+
+/// Re-evaluates `Interest` for every registered callsite, across every
+/// registered dispatcher.
+///
+/// Interest is normally computed once, when a callsite or dispatcher is
+/// first registered. A `Subscriber` whose filtering configuration can
+/// change at runtime (for instance, a log level that can be raised, or a
+/// span newly selected for tracing) has no other way to tell
+/// already-registered callsites to recompute their cached `Interest`. Call
+/// this after changing a subscriber's filter to bring the whole registry
+/// back in sync; see [`rebuild_interest_for`] for a cheaper variant that
+/// only needs to account for a single dispatcher.
+///
+/// [`rebuild_interest_for`]: ::callsite::rebuild_interest_for
+pub fn rebuild_interest() {
+    let mut dispatchers = REGISTRY.dispatchers.write().unwrap();
+    rebuild_interest_locked(&mut dispatchers);
+}
+
+/// Re-evaluates `Interest` for every registered callsite against a single
+/// `dispatch`, without re-locking (or disturbing the interest contributed
+/// by) every other registered dispatcher.
+///
+/// Because this only asks one dispatcher for a fresh answer, it can't
+/// retract interest that dispatcher previously granted without also
+/// erasing interest contributed by other dispatchers; it can only ever
+/// escalate a callsite's interest. If a filter change needs to *disable*
+/// callsites, use the crate-wide [`rebuild_interest`] instead.
+///
+/// [`rebuild_interest`]: ::callsite::rebuild_interest
+pub fn rebuild_interest_for(dispatch: &Dispatch) {
+    for callsite in REGISTRY.callsites.iter() {
+        let interest = dispatch.register_callsite(callsite.metadata());
+        callsite.add_interest(interest);
+    }
+    INTEREST_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the current interest generation.
+///
+/// This counter is bumped every time [`rebuild_interest`] or
+/// [`rebuild_interest_for`] recomputes interest for the registry, and also
+/// whenever a new dispatcher is registered (since that can escalate
+/// interest for every pre-existing callsite too). Code that caches a
+/// callsite's `Interest` can compare against a previously observed
+/// generation to cheaply tell whether that cache might now be stale,
+/// without needing to re-lock the dispatcher registry on every check.
+///
+/// [`rebuild_interest`]: ::callsite::rebuild_interest
+/// [`rebuild_interest_for`]: ::callsite::rebuild_interest_for
+pub fn interest_generation() -> usize {
+    INTEREST_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Clears cached interest on every registered callsite and recomputes it
+/// from scratch against `dispatchers`, pruning any registrar that can no
+/// longer be upgraded.
+fn rebuild_interest_locked(dispatchers: &mut Vec<dispatcher::Registrar>) {
+    // Prune dead registrars first, and independently of whether there are
+    // any callsites to test against: a registrar's liveness doesn't depend
+    // on `REGISTRY.callsites` being non-empty, so basing the `retain` below
+    // on "did registering any callsite succeed" would evict every live
+    // dispatcher as soon as there happened to be zero callsites registered.
+    dispatchers.retain(|registrar| registrar.is_alive());
+    for callsite in REGISTRY.callsites.iter() {
+        callsite.remove_interest();
+        for registrar in dispatchers.iter() {
+            if let Some(interest) = registrar.try_register(callsite.metadata()) {
+                callsite.add_interest(interest);
+            }
+        }
+    }
+    INTEREST_GENERATION.fetch_add(1, Ordering::Relaxed);
 }
 
 /// Reset the registry. This is typically only useful in tests.
+///
+/// This clears the registered dispatchers, and resets the callsite list so
+/// that previously registered callsites are no longer visible to
+/// `register_dispatch`/`rebuild_interest`. The `Node`s backing those
+/// callsites are still leaked rather than freed (callsites are `'static`
+/// and live for the rest of the program), but the list itself comes back
+/// empty, as if nothing had been registered.
 #[cfg(any(test, feature = "test-support"))]
 pub fn reset_registry() {
-    let mut registry = REGISTRY.lock().unwrap();
-    registry.callsites.clear();
-    registry.dispatchers.clear();
+    REGISTRY.callsites.reset();
+    REGISTRY.dispatchers.write().unwrap().clear();
 }
 
 // ===== impl Callsite =====
 
 impl Callsite + 'static {
     /// Returns an `Identifier` unique to this `Callsite`.
-    // TODO: can this just be public API?
-    pub(crate) fn id(&'static self) -> Identifier {
+    pub fn id(&'static self) -> Identifier {
         Identifier::from_callsite(self)
     }
 }
@@ -106,10 +309,20 @@ impl Callsite + 'static {
 
 impl Identifier {
     /// Returns an `Identifier` unique to the provided `Callsite`.
-    // TODO: can this just be public API?
-    pub(crate) fn from_callsite(callsite: &'static Callsite) -> Self {
+    pub fn from_callsite(callsite: &'static Callsite) -> Self {
         Identifier(callsite)
     }
+
+    /// Returns the [metadata] of the `Callsite` this `Identifier` refers to.
+    ///
+    /// This lets a `Subscriber` that has stashed an `Identifier` (e.g. as a
+    /// map key for a per-callsite cache) recover the callsite's metadata
+    /// without having to thread a `&Meta` alongside it separately.
+    ///
+    /// [metadata]: ::Meta
+    pub fn metadata(&self) -> &'static Meta {
+        self.0.metadata()
+    }
 }
 
 impl PartialEq for Identifier {
@@ -133,4 +346,105 @@ impl Hash for Identifier {
     {
         (self.0 as *const Callsite).hash(state)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Barrier,
+    };
+    use std::thread;
+    use {Kind, Level};
+
+    struct TestCallsite {
+        meta: Meta<'static>,
+        interests_applied: AtomicUsize,
+    }
+
+    impl TestCallsite {
+        fn new(meta: Meta<'static>) -> Self {
+            TestCallsite {
+                meta,
+                interests_applied: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Callsite for TestCallsite {
+        fn add_interest(&self, _interest: Interest) {
+            self.interests_applied.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn remove_interest(&self) {
+            self.interests_applied.store(0, Ordering::SeqCst);
+        }
+
+        fn metadata(&self) -> &Meta {
+            &self.meta
+        }
+    }
+
+    // A subscriber that only cares about filtering, so it doesn't need to
+    // implement the rest of `Subscriber`'s span/event-recording methods.
+    struct AlwaysOn;
+
+    impl Subscriber for AlwaysOn {
+        fn register_callsite(&self, _meta: &Meta) -> Interest {
+            Interest::always()
+        }
+    }
+
+    lazy_static! {
+        static ref CALLSITE: TestCallsite = TestCallsite::new(Meta::new(
+            Some("test_callsite"),
+            module_path!(),
+            Level::TRACE,
+            Some(file!()),
+            Some(line!()),
+            &[],
+            Kind::EVENT,
+        ));
+    }
+
+    /// Regression test for a lost-registration race that a naive lock-free
+    /// rewrite of `register` can reintroduce: a callsite registering
+    /// concurrently with a brand new dispatcher must still end up with that
+    /// dispatcher's interest applied, even when `register_dispatch` wins the
+    /// race to scan `REGISTRY.callsites`.
+    #[test]
+    fn concurrent_register_and_register_dispatch_is_not_lost() {
+        reset_registry();
+        let barrier = Arc::new(Barrier::new(2));
+        // Keep the dispatcher alive until after the assertion: once it is
+        // dropped, its interest is (correctly) removed from every callsite.
+        let dispatch = Dispatch::new(AlwaysOn);
+
+        let registering = {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                register(&*CALLSITE);
+            })
+        };
+        let dispatching = {
+            let barrier = barrier.clone();
+            let dispatch = dispatch.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                register_dispatch(&dispatch);
+            })
+        };
+
+        registering.join().unwrap();
+        dispatching.join().unwrap();
+
+        assert!(
+            CALLSITE.interests_applied.load(Ordering::SeqCst) > 0,
+            "a callsite registered concurrently with a new dispatcher never \
+             received that dispatcher's interest"
+        );
+        drop(dispatch);
+    }
+}
@@ -0,0 +1,76 @@
+//! Dispatches trace events to `Subscriber`s.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Weak,
+};
+use {callsite, subscriber::Interest, Meta, Subscriber};
+
+/// `Dispatch` trace data to a [`Subscriber`].
+///
+/// [`Subscriber`]: ::Subscriber
+#[derive(Clone)]
+pub struct Dispatch {
+    subscriber: Arc<Subscriber + Send + Sync>,
+    // Set the first time this `Dispatch` is handed to `register_dispatch`.
+    // Dropping a `Dispatch` that was never registered (e.g. a throwaway one
+    // built just to inspect a subscriber) has nothing to resync, so this
+    // lets `Drop` skip the registry rebuild entirely in that case.
+    registered: Arc<AtomicBool>,
+}
+
+/// A non-owning reference to a `Dispatch`'s `Subscriber`, used to determine
+/// whether that `Subscriber` is still live without keeping it alive.
+#[derive(Clone)]
+pub(crate) struct Registrar(Weak<Subscriber + Send + Sync>);
+
+impl Dispatch {
+    /// Returns a new `Dispatch` that sends trace data to `subscriber`.
+    pub fn new<S>(subscriber: S) -> Self
+    where
+        S: Subscriber + Send + Sync + 'static,
+    {
+        Dispatch {
+            subscriber: Arc::new(subscriber),
+            registered: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn registrar(&self) -> Registrar {
+        self.registered.store(true, Ordering::Relaxed);
+        Registrar(Arc::downgrade(&self.subscriber))
+    }
+
+    pub(crate) fn register_callsite(&self, metadata: &Meta) -> Interest {
+        self.subscriber.register_callsite(metadata)
+    }
+}
+
+impl Drop for Dispatch {
+    fn drop(&mut self) {
+        // We'd like to only resync the registry when the *last* `Dispatch`
+        // clone pointing at a registered subscriber is dropped, but
+        // `Arc::strong_count` can't tell us that under concurrent drops: a
+        // custom `Drop::drop` body runs before the compiler's field-drop
+        // glue decrements the `Arc`, so two threads dropping the last two
+        // clones at the same time can each observe the other's clone as
+        // still live and both skip the resync, leaking stale `Interest`.
+        // Instead, just always resync when this `Dispatch` was ever
+        // registered; `rebuild_interest_locked`'s `is_alive` check makes the
+        // call a no-op if other clones of the subscriber are still around.
+        if self.registered.load(Ordering::Relaxed) {
+            callsite::reset_dispatch();
+        }
+    }
+}
+
+impl Registrar {
+    /// Returns `true` if the `Subscriber` this registrar points to still
+    /// exists.
+    pub(crate) fn is_alive(&self) -> bool {
+        self.0.upgrade().is_some()
+    }
+
+    pub(crate) fn try_register(&self, metadata: &Meta) -> Option<Interest> {
+        self.0.upgrade().map(|subscriber| subscriber.register_callsite(metadata))
+    }
+}